@@ -8,34 +8,83 @@ use std::{fs, path::PathBuf, time::Duration};
 use tempfile::TempDir;
 use tokio::{
     fs::File,
-    io::{AsyncRead, AsyncReadExt},
+    io::{AsyncRead, AsyncReadExt, BufReader},
 };
 use tokio_tar::Archive;
 use tokio_util::io::StreamReader;
 
-/// Fetch the crate archive for the given version from crates.io
-async fn fetch_crate_archive(
-    crate_name: &str,
-    version: &Version,
-) -> Result<Archive<impl AsyncRead>> {
-    let package_url = format!(
-        "https://static.crates.io/crates/{}/{}-{}.crate",
-        crate_name, crate_name, version
-    );
-    let response = reqwest::get(&package_url).await?;
+/// Directory `.crate` archives are cached in, keyed by `<name>-<version>.crate`.
+fn cache_dir() -> Result<PathBuf> {
+    let dir = dirs::cache_dir()
+        .ok_or_else(|| anyhow!("Could not determine user cache directory"))?
+        .join("cargo-fork");
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    Ok(dir)
+}
+
+fn cached_archive_path(crate_name: &str, version: &Version) -> Result<PathBuf> {
+    Ok(cache_dir()?.join(format!("{}-{}.crate", crate_name, version)))
+}
+
+/// Remove every cached `.crate` archive.
+pub(crate) fn clear_cache() -> Result<()> {
+    let dir = cache_dir()?;
+    for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Return the `.crate` archive for the given version, downloading it from
+/// static.crates.io into the local cache on a miss.
+async fn fetch_crate_archive_file(crate_name: &str, version: &Version) -> Result<File> {
+    let cache_path = cached_archive_path(crate_name, version)?;
+
+    if !cache_path.try_exists()? {
+        let package_url = format!(
+            "https://static.crates.io/crates/{}/{}-{}.crate",
+            crate_name, crate_name, version
+        );
+        println!("fetching: {}", package_url);
+        let response = reqwest::get(&package_url).await?;
+
+        if response.status() != reqwest::StatusCode::OK {
+            bail!("Failed to fetch {}: {}", package_url, response.status());
+        }
 
-    if response.status() != reqwest::StatusCode::OK {
-        bail!("Failed to fetch {}: {}", package_url, response.status());
+        // Issue #1: this should be an AsyncRead
+        let stream = response
+            .bytes_stream()
+            .map_err(|err: reqwest::Error| std::io::Error::new(std::io::ErrorKind::Other, err));
+
+        // Issue #2: Cannot use "futures-io", have to use "tokio" for this adapter.
+        let mut body = StreamReader::new(stream);
+
+        // Download to a process-unique temp file first, so a crash mid-download can't
+        // leave a truncated file behind under the real cache key, and two concurrent
+        // fetches of the same crate/version can't interleave writes to the same path.
+        let named_tmp_file = tempfile::NamedTempFile::new_in(cache_dir()?)
+            .context("Failed to create temp file for download")?;
+        let tmp_path = named_tmp_file.path().to_path_buf();
+        let mut tmp_file = File::from_std(named_tmp_file.into_file());
+        tokio::io::copy(&mut body, &mut tmp_file).await?;
+        drop(tmp_file);
+        fs::rename(&tmp_path, &cache_path)?;
     }
 
-    // Issue #1: this should be an AsyncRead
-    let stream = response
-        .bytes_stream()
-        .map_err(|err: reqwest::Error| std::io::Error::new(std::io::ErrorKind::Other, err));
+    Ok(File::open(&cache_path).await?)
+}
 
-    // Issue #2: Cannot use "futures-io", have to use "tokio" for this adapter.
-    let buf = StreamReader::new(stream);
-    let dec = GzipDecoder::new(buf);
+/// Fetch the crate archive for the given version from crates.io (or the local cache)
+async fn fetch_crate_archive(
+    crate_name: &str,
+    version: &Version,
+) -> Result<Archive<impl AsyncRead>> {
+    let file = fetch_crate_archive_file(crate_name, version).await?;
+    let dec = GzipDecoder::new(BufReader::new(file));
 
     /*
             Issue #3:
@@ -2,6 +2,7 @@
 ///
 mod crates_io;
 mod manifest;
+mod verify;
 
 use anyhow::{anyhow, bail, Context, Result};
 
@@ -20,10 +21,15 @@ use std::{
 
 use crate::{
     crates_io::crate_get_repo,
-    manifest::{find_package, parse_manifest, query_metadata},
+    manifest::{
+        find_one_package, find_package, find_package_version, manifest_remove_patch, parse_manifest,
+        query_metadata,
+    },
 };
+use crate::crates_io::clear_cache;
 use crate::{crates_io::lookup_vcs_for_version, manifest::manifest_insert_patch};
 use crate::{crates_io::unpack_crate_archive, manifest::diff_deps};
+use crate::verify::verify_vcs_matches_archive;
 
 #[derive(clap::ValueEnum, Debug, Clone, Eq, PartialEq)]
 enum Source {
@@ -37,18 +43,117 @@ enum Source {
 
 #[derive(clap::Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
-struct Args {
+struct Cli {
+    #[clap(subcommand)]
+    command: Commands,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Commands {
+    /// Patch a dependency with a local checkout of its source
+    Fork(ForkArgs),
+    /// Revert a previous `fork`, removing the patch and re-resolving dependencies
+    Unfork(UnforkArgs),
+    /// Remove all cached `.crate` archives
+    ClearCache,
+}
+
+#[derive(clap::Args, Debug)]
+struct ForkArgs {
     #[arg(long, value_enum, default_value_t = Source::VCSCurrent)]
     source: Source,
 
     #[arg(long)]
     dest_dir: Option<PathBuf>,
 
+    /// After checking out with `--source vcs-current`, verify the checkout
+    /// reproduces the published crate archive byte-for-byte
+    #[arg(long)]
+    verify: bool,
+
+    /// SSH private key to use for `git@...` remotes, overriding the ssh-agent
+    /// identity and `~/.ssh/id_*` discovery
+    #[arg(long)]
+    ssh_key: Option<PathBuf>,
+
+    /// Check out this git repository instead of looking up `crate_name`'s
+    /// `repository` field on crates.io. Mutually exclusive with `--path`.
+    #[arg(long)]
+    git: Option<Url>,
+
+    /// Revision to check out when `--git` is given (default: HEAD)
+    #[arg(long)]
+    rev: Option<String>,
+
+    /// Patch with this local directory instead of resolving a source from
+    /// crates.io. Mutually exclusive with `--git`.
+    #[arg(long)]
+    path: Option<PathBuf>,
+
+    #[arg()]
+    crate_name: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct UnforkArgs {
     #[arg()]
     crate_name: String,
+
+    /// Also delete the checkout directory that `fork` created. Directories supplied
+    /// via `--path`, or a `--dest-dir` that already existed before `fork` ran, were
+    /// never created by `fork` and are left alone regardless of this flag.
+    #[arg(long)]
+    delete_checkout: bool,
 }
 
-fn git_checkout(repo_url: &Url, vcs_revision: &str, checkout_dir: &Path) -> Result<()> {
+/// Resolve credentials for `git_checkout`'s clone, following git's usual precedence:
+/// for SSH remotes, `ssh_key_override` when given, otherwise the ssh-agent identity,
+/// falling back to an on-disk key pair (`~/.ssh/id_*`); for HTTPS, the
+/// `CARGO_FORK_TOKEN` env var, falling back to the system git credential helper.
+fn git_credentials_callback(
+    ssh_key_override: Option<PathBuf>,
+) -> impl FnMut(&str, Option<&str>, git2::CredentialType) -> std::result::Result<git2::Cred, git2::Error> {
+    move |url, username_from_url, allowed_types| {
+        let username = username_from_url.unwrap_or("git");
+
+        if allowed_types.contains(git2::CredentialType::SSH_KEY) {
+            if let Some(key_path) = &ssh_key_override {
+                return git2::Cred::ssh_key(username, None, key_path, None);
+            }
+            if let Ok(cred) = git2::Cred::ssh_key_from_agent(username) {
+                return Ok(cred);
+            }
+            if let Some(home) = dirs::home_dir() {
+                for candidate in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+                    let key_path = home.join(".ssh").join(candidate);
+                    if key_path.exists() {
+                        return git2::Cred::ssh_key(username, None, &key_path, None);
+                    }
+                }
+            }
+        }
+
+        if allowed_types.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+            if let Ok(token) = std::env::var("CARGO_FORK_TOKEN") {
+                return git2::Cred::userpass_plaintext(&token, "");
+            }
+            if let Ok(config) = git2::Config::open_default() {
+                if let Ok(cred) = git2::Cred::credential_helper(&config, url, username_from_url) {
+                    return Ok(cred);
+                }
+            }
+        }
+
+        git2::Cred::default()
+    }
+}
+
+fn git_checkout(
+    repo_url: &Url,
+    vcs_revision: &str,
+    checkout_dir: &Path,
+    ssh_key: Option<PathBuf>,
+) -> Result<()> {
     let repo = if checkout_dir.try_exists()? {
         // TODO: make sure it fails if repo is dirty
         Repository::open(&checkout_dir)
@@ -60,6 +165,7 @@ fn git_checkout(repo_url: &Url, vcs_revision: &str, checkout_dir: &Path) -> Resu
             //println!("{}: {}", progress.received_objects(), progress.total_objects());
             true
         });
+        cb.credentials(git_credentials_callback(ssh_key));
         let mut fetch_opts = FetchOptions::new();
         fetch_opts.remote_callbacks(cb);
         RepoBuilder::new()
@@ -87,107 +193,290 @@ fn git_checkout(repo_url: &Url, vcs_revision: &str, checkout_dir: &Path) -> Resu
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+fn cargo_update_package(manifest_path: &Path, workspace_root: &Path, crate_name: &str) -> Result<()> {
+    Command::new("cargo")
+        .args(&[
+            "update",
+            "--quiet",
+            "--workspace",
+            "--package",
+            crate_name,
+            "--manifest-path",
+            manifest_path.to_str().unwrap(),
+        ])
+        .current_dir(workspace_root)
+        .status()?;
 
-    // TOOD: this assumes running from package root
-    let crate_root = PathBuf::from(".");
-    let metadata_before = query_metadata(&crate_root)?;
+    Ok(())
+}
 
-    let workspace_root = metadata_before.workspace_root.as_std_path();
-    let manifest_path = workspace_root.join("Cargo.toml");
+/// Infer the subdirectory a crate lives at within its own checked-out repo, for the
+/// case where we don't already know it from `.cargo_vcs_info.json` (e.g. the repo
+/// wasn't resolved via crates.io at all).
+fn infer_path_in_repo(checkout_dir: &Path, crate_name: &str) -> Result<PathBuf> {
+    let dep_metadata = query_metadata(checkout_dir)?;
+    let pkg = find_one_package(
+        // Would be nice for references to be handled consistantly
+        // without this explicit deref
+        dep_metadata.workspace_packages().iter().map(|p| *p),
+        crate_name,
+    )?;
+
+    Ok(pkg
+        .manifest_path
+        .parent()
+        .unwrap()
+        .strip_prefix(checkout_dir)
+        .unwrap()
+        .to_path_buf()
+        .into_std_path_buf())
+}
 
-    let mut manifest = parse_manifest(&manifest_path)?;
-    println!("Using manifest: {}", manifest_path.display());
+/// Marker file dropped inside a patch directory that `fork` created itself (a fresh
+/// git clone or crate unpack), as opposed to one the user pointed us at via `--path`
+/// or a pre-existing `--dest-dir`. `unfork --delete-checkout` checks for this before
+/// removing anything, so it can never delete a directory it didn't create.
+const OWNED_CHECKOUT_MARKER: &str = ".cargo-fork-checkout";
 
-    // TODO: handle multiple versions in dependency tree
-    let package_meta = find_package(metadata_before.packages.iter(), &args.crate_name)?;
+fn mark_owned_checkout(patch_dir: &Path) -> Result<()> {
+    fs::write(patch_dir.join(OWNED_CHECKOUT_MARKER), "")
+        .with_context(|| format!("Failed to mark {} as a fork-owned checkout", patch_dir.display()))
+}
 
-    let workspace_parent = workspace_root.parent().unwrap();
+fn is_owned_checkout(patch_dir: &Path) -> bool {
+    patch_dir.join(OWNED_CHECKOUT_MARKER).try_exists().unwrap_or(false)
+}
+
+/// Check out (or unpack) the source for one version of `crate_name`, returning the
+/// directory that should be patched in. `dir_suffix`, when set, is appended to the
+/// checkout/unpack directory name so that multiple versions of the same crate don't
+/// collide on disk.
+async fn checkout_patch_source(
+    args: &ForkArgs,
+    workspace_parent: &Path,
+    crate_name: &str,
+    version: &semver::Version,
+    dir_suffix: Option<&str>,
+) -> Result<PathBuf> {
+    if let Some(path) = &args.path {
+        // The user's own directory: fork didn't create it, so it's never ours to delete.
+        return Ok(path.clone());
+    }
 
-    let patch_dir = if args.source == Source::Crate {
+    if let Some(git_url) = &args.git {
+        let final_segment = git_url
+            .path_segments()
+            .and_then(|mut segs| segs.next_back())
+            .filter(|segment| !segment.is_empty())
+            .with_context(|| format!("--git URL {} has no path segment to name the checkout directory after", git_url))?;
+        let dir_name = match dir_suffix {
+            Some(suffix) => format!("{}-{}", final_segment, suffix),
+            None => final_segment.to_string(),
+        };
+        let checkout_dir = args
+            .dest_dir
+            .clone()
+            .unwrap_or_else(|| workspace_parent.join(&dir_name));
+        let owned = !checkout_dir.try_exists()?;
+
+        let revision = args.rev.clone().unwrap_or_else(|| "HEAD".to_string());
+        git_checkout(git_url, &revision, &checkout_dir, args.ssh_key.clone())?;
+
+        let path_in_repo = infer_path_in_repo(&checkout_dir, crate_name)?;
+        let patch_dir = checkout_dir.join(path_in_repo);
+        if owned {
+            mark_owned_checkout(&patch_dir)?;
+        }
+        return Ok(patch_dir);
+    }
+
+    if args.source == Source::Crate {
         let tmpdir = tempfile::TempDir::new()?;
-        let archive_root =
-            unpack_crate_archive(&tmpdir, &args.crate_name, &package_meta.version).await?;
+        let archive_root = unpack_crate_archive(&tmpdir, crate_name, version).await?;
+
+        let dir_name = match dir_suffix {
+            Some(suffix) => PathBuf::from(format!("{}-{}", archive_root.display(), suffix)),
+            None => archive_root.clone(),
+        };
 
         let patch_root = args
             .dest_dir
-            .unwrap_or_else(|| workspace_parent.join(&archive_root));
+            .clone()
+            .unwrap_or_else(|| workspace_parent.join(&dir_name));
         if patch_root.try_exists()? {
             bail!("Patch directory {} already exists", patch_root.display());
         }
         fs::rename(&tmpdir.path().join(&archive_root), &patch_root)?;
-        patch_root
+        // Always fresh: we just bailed above if it already existed.
+        mark_owned_checkout(&patch_root)?;
+        return Ok(patch_root);
+    }
+
+    // Default: resolve the crate's repository from crates.io and check out either its
+    // HEAD or the revision that produced the version we're patching.
+    let repo_url = crate_get_repo(crate_name).await?;
+
+    // https://github.com/ahupp/cargo-fork -> cargo-fork
+    let final_segment = repo_url.path_segments().unwrap().last().unwrap();
+    let dir_name = match dir_suffix {
+        Some(suffix) => format!("{}-{}", final_segment, suffix),
+        None => final_segment.to_string(),
+    };
+
+    let checkout_dir = args
+        .dest_dir
+        .clone()
+        .unwrap_or_else(|| workspace_parent.join(&dir_name));
+    let owned = !checkout_dir.try_exists()?;
+
+    let vcs_info = lookup_vcs_for_version(crate_name, version).await?;
+
+    let revision = if args.source == Source::VCSHead {
+        "HEAD".to_string()
     } else {
-        let repo_url = crate_get_repo(&args.crate_name).await?;
+        let vcinfo = vcs_info.as_ref().ok_or_else(|| {
+            anyhow!(
+                "No .cargo_vcs_info.json for package {}:{}, try --source vcs-head",
+                crate_name,
+                version
+            )
+        })?;
+        vcinfo.hash.clone()
+    };
 
-        // https://github.com/ahupp/cargo-fork -> cargo-fork
-        let final_segment = repo_url.path_segments().unwrap().last().unwrap();
+    git_checkout(&repo_url, &revision, &checkout_dir, args.ssh_key.clone())?;
 
-        let checkout_dir = args
-            .dest_dir
-            .unwrap_or_else(|| workspace_parent.join(&final_segment));
+    let path_in_repo = match vcs_info {
+        Some(vcs_info) => vcs_info.path_in_vcs,
+        // Infer path in vcs when package is in a workspace and path_in_vcs is not specified in crate_vcs_info.json
+        None => infer_path_in_repo(&checkout_dir, crate_name)?,
+    };
 
-        let vcs_info = lookup_vcs_for_version(&args.crate_name, &package_meta.version).await?;
+    let patch_dir = checkout_dir.join(path_in_repo);
 
-        let revision = if args.source == Source::VCSHead {
-            "HEAD"
-        } else {
-            let vcinfo = vcs_info.as_ref().ok_or_else(|| {
-                anyhow!(
-                    "No .cargo_vcs_info.json for package {}:{}, try --source-vcs-head",
-                    args.crate_name,
-                    package_meta.version
-                )
-            })?;
-            &vcinfo.hash
-        };
+    // Verify before dropping the ownership marker, so the marker isn't reported as an
+    // extra file in the checkout.
+    if args.verify && args.source == Source::VCSCurrent {
+        verify_patch_source(crate_name, version, &patch_dir).await?;
+    }
+
+    if owned {
+        mark_owned_checkout(&patch_dir)?;
+    }
+
+    Ok(patch_dir)
+}
+
+/// Unpack the published crate archive for `crate_name`/`version` and compare it
+/// against the VCS checkout at `vcs_dir`, warning if they don't match. This is what
+/// lets `--verify` catch a `vcs-current` checkout that doesn't actually reproduce
+/// what was uploaded to crates.io.
+async fn verify_patch_source(crate_name: &str, version: &semver::Version, vcs_dir: &Path) -> Result<()> {
+    let tmpdir = tempfile::TempDir::new()?;
+    let archive_root = unpack_crate_archive(&tmpdir, crate_name, version).await?;
+    let archive_dir = tmpdir.path().join(&archive_root);
+
+    let report = verify_vcs_matches_archive(vcs_dir, &archive_dir)?;
+    if report.is_clean() {
+        println!(
+            "verify: {} reproduces the published {}-{} archive",
+            vcs_dir.display(),
+            crate_name,
+            version
+        );
+    } else {
+        println!(
+            "warning: {} does not reproduce the published {}-{} archive; this vcs-current patch may not be byte-equivalent to the dependency it replaces",
+            vcs_dir.display(),
+            crate_name,
+            version
+        );
+        for path in &report.mismatched {
+            println!("  ~ {} (differs)", path.display());
+        }
+        for path in &report.missing_from_vcs {
+            println!("  - {} (in archive, missing from checkout)", path.display());
+        }
+        for path in &report.extra_in_vcs {
+            println!("  + {} (in checkout, not in archive)", path.display());
+        }
+    }
+
+    Ok(())
+}
 
-        git_checkout(&repo_url, revision, &checkout_dir)?;
+async fn fork(args: ForkArgs) -> Result<()> {
+    if args.git.is_some() && args.path.is_some() {
+        bail!("--git and --path cannot be used together");
+    }
+    if args.rev.is_some() && args.git.is_none() {
+        bail!("--rev can only be used with --git");
+    }
 
-        let path_in_repo = if let Some(vcs_info) = vcs_info {
-            vcs_info.path_in_vcs
+    // TOOD: this assumes running from package root
+    let crate_root = PathBuf::from(".");
+    let metadata_before = query_metadata(&crate_root)?;
+
+    let workspace_root = metadata_before.workspace_root.as_std_path();
+    let manifest_path = workspace_root.join("Cargo.toml");
+
+    let mut manifest = parse_manifest(&manifest_path)?;
+    println!("Using manifest: {}", manifest_path.display());
+
+    let package_metas = find_package(metadata_before.packages.iter(), &args.crate_name)?;
+
+    if package_metas.len() > 1 && (args.dest_dir.is_some() || args.path.is_some() || args.git.is_some()) {
+        bail!(
+            "--dest-dir/--path/--git can't be used when {} resolves to multiple versions ({}); \
+             there's no way to point a single directory or git revision at more than one of them",
+            args.crate_name,
+            package_metas
+                .iter()
+                .map(|p| p.version.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    let workspace_parent = workspace_root.parent().unwrap();
+    let multiple_versions = package_metas.len() > 1;
+
+    let mut patch_keys = Vec::new();
+
+    for (i, package_meta) in package_metas.iter().enumerate() {
+        let patch_key = if multiple_versions {
+            format!("{}-{}", args.crate_name, i + 1)
         } else {
-            // Infer path in vcs when package is in a workspace and path_in_vcs is not specified in crate_vcs_info.json
-            let dep_metadata = query_metadata(&checkout_dir)?;
-            let pkg = find_package(
-                // Would be nice for references to be handled consistantly
-                // without this explicit deref
-                dep_metadata.workspace_packages().iter().map(|p| *p),
-                &args.crate_name,
-            )?;
-
-            pkg.manifest_path
-                .parent()
-                .unwrap()
-                .strip_prefix(&checkout_dir)
-                .unwrap()
-                .to_path_buf()
-                .into_std_path_buf()
+            args.crate_name.clone()
         };
+        let dir_suffix = multiple_versions.then(|| package_meta.version.to_string());
 
-        checkout_dir.join(path_in_repo)
-    };
+        let patch_dir = checkout_patch_source(
+            &args,
+            workspace_parent,
+            &args.crate_name,
+            &package_meta.version,
+            dir_suffix.as_deref(),
+        )
+        .await?;
+
+        manifest_insert_patch(
+            &mut manifest,
+            &patch_key,
+            &args.crate_name,
+            &package_meta.version,
+            &patch_dir,
+        )?;
+
+        patch_keys.push((patch_key, package_meta.version.clone()));
+    }
 
-    manifest_insert_patch(&mut manifest, &args.crate_name, &patch_dir)?;
     println!("writing manifest: {}", manifest_path.display());
 
     std::fs::write(&manifest_path, manifest.data.to_string().as_bytes())
         .context("Failed to write updated Cargo.toml")?;
 
-    Command::new("cargo")
-        .args(&[
-            "update",
-            "--quiet",
-            "--workspace",
-            "--package",
-            &args.crate_name,
-            "--manifest-path",
-            &manifest_path.to_str().unwrap(),
-        ])
-        .current_dir(&workspace_root)
-        .status()?;
+    cargo_update_package(&manifest_path, &workspace_root, &args.crate_name)?;
 
     let metadata_after = query_metadata(&crate_root)?;
     let diffs = diff_deps(&metadata_before, &metadata_after);
@@ -202,7 +491,83 @@ async fn main() -> Result<()> {
         }
     }
 
-    // TODO: detect if patch is unused anywhere, and flag it noisily
+    for (patch_key, version) in &patch_keys {
+        // A patch is live if the graph now resolves this name/version to our path
+        // source rather than the original registry source.
+        let is_live = find_package_version(metadata_after.packages.iter(), &args.crate_name, version)
+            .map(|p| p.source.is_none())
+            .unwrap_or(false);
+
+        if !is_live {
+            println!(
+                "warning: patch \"{}\" ({} {}) does not override anything in the resolved dependency graph",
+                patch_key, args.crate_name, version
+            );
+            println!(
+                "  likely cause: a version requirement elsewhere in the tree doesn't accept {} {}, or it only appears transitively under an incompatible version",
+                args.crate_name, version
+            );
+        }
+    }
 
     Ok(())
 }
+
+fn unfork(args: UnforkArgs) -> Result<()> {
+    // TOOD: this assumes running from package root
+    let crate_root = PathBuf::from(".");
+    let metadata = query_metadata(&crate_root)?;
+
+    let workspace_root = metadata.workspace_root.as_std_path();
+    let manifest_path = workspace_root.join("Cargo.toml");
+
+    let mut manifest = parse_manifest(&manifest_path)?;
+    println!("Using manifest: {}", manifest_path.display());
+
+    let patch_dirs = manifest_remove_patch(&mut manifest, &args.crate_name)?;
+    if patch_dirs.is_empty() {
+        bail!(
+            "No patch for {} found in {}",
+            args.crate_name,
+            manifest_path.display()
+        );
+    }
+
+    println!("writing manifest: {}", manifest_path.display());
+    std::fs::write(&manifest_path, manifest.data.to_string().as_bytes())
+        .context("Failed to write updated Cargo.toml")?;
+
+    cargo_update_package(&manifest_path, &workspace_root, &args.crate_name)?;
+
+    if args.delete_checkout {
+        for patch_dir in patch_dirs {
+            if !patch_dir.try_exists()? {
+                println!(
+                    "checkout directory {} does not exist, nothing to remove",
+                    patch_dir.display()
+                );
+            } else if !is_owned_checkout(&patch_dir) {
+                println!(
+                    "refusing to delete {}: it wasn't created by `fork` (supplied via --path, or a --dest-dir that already existed), skipping",
+                    patch_dir.display()
+                );
+            } else {
+                println!("removing checkout: {}", patch_dir.display());
+                fs::remove_dir_all(&patch_dir)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Commands::Fork(args) => fork(args).await,
+        Commands::Unfork(args) => unfork(args),
+        Commands::ClearCache => clear_cache(),
+    }
+}
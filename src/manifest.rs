@@ -1,11 +1,12 @@
 use std::{
     collections::{HashMap, HashSet},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use anyhow::*;
 use cargo_edit::Manifest;
 use cargo_metadata::{Metadata, Package, PackageId};
+use semver::Version;
 use toml_edit::{InlineTable, Item, Table, Value};
 
 pub(crate) fn diff_deps<'a>(
@@ -67,22 +68,28 @@ fn toml_get_or_create_table_by_path<'a>(
     Ok(current)
 }
 
+/// Insert a `[patch.crates-io]` entry under `patch_key`, pointing `package_name` at
+/// `patch_dir`. `version` is recorded as an exact requirement so the patch only
+/// applies to the matching node in the dependency graph; this is what lets the same
+/// crate be patched at more than one semver-incompatible version, each under its own
+/// key (e.g. `serde-1` / `serde-2`).
+/// See https://doc.rust-lang.org/cargo/reference/overriding-dependencies.html#using-patch-with-multiple-versions
 pub(crate) fn manifest_insert_patch(
     manifest: &mut Manifest,
+    patch_key: &str,
     package_name: &str,
+    version: &Version,
     patch_dir: &Path,
 ) -> Result<()> {
     let root_table = manifest.data.as_table_mut();
 
     let patch_table = toml_get_or_create_table_by_path(&["patch", "crates-io"], root_table)?;
 
-    patch_table[&package_name] = {
+    patch_table[patch_key] = {
         let mut dep = InlineTable::new();
         dep.insert("path", Value::from(patch_dir.to_str().unwrap()));
-        // Explicitly set package name to handle cases where we patch multiple versions
-        // see https://doc.rust-lang.org/cargo/reference/overriding-dependencies.html#using-patch-with-multiple-versions
-        // TODO: rename key to support multiple patched versions
-        dep.insert("package", Value::from(package_name.clone()));
+        dep.insert("package", Value::from(package_name));
+        dep.insert("version", Value::from(format!("={}", version)));
 
         Item::Value(Value::InlineTable(dep))
     };
@@ -90,6 +97,64 @@ pub(crate) fn manifest_insert_patch(
     Ok(())
 }
 
+/// Remove every `[patch.crates-io]` entry whose `package` is `package_name`, pruning
+/// the `crates-io`/`patch` tables if they become empty. There may be more than one
+/// entry if the crate was patched at multiple versions. Returns the `path` of each
+/// entry that was removed.
+pub(crate) fn manifest_remove_patch(
+    manifest: &mut Manifest,
+    package_name: &str,
+) -> Result<Vec<PathBuf>> {
+    let root_table = manifest.data.as_table_mut();
+
+    let patch_table = match root_table.get_mut("patch") {
+        Some(item) => item
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("Maformed Cargo.toml"))?,
+        None => return Ok(Vec::new()),
+    };
+
+    let crates_io_table = match patch_table.get_mut("crates-io") {
+        Some(item) => item
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("Maformed Cargo.toml"))?,
+        None => return Ok(Vec::new()),
+    };
+
+    let keys_to_remove: Vec<String> = crates_io_table
+        .iter()
+        .filter(|(_, item)| {
+            item.as_inline_table()
+                .and_then(|t| t.get("package"))
+                .and_then(|v| v.as_str())
+                == Some(package_name)
+        })
+        .map(|(key, _)| key.to_string())
+        .collect();
+
+    let mut patch_dirs = Vec::new();
+    for key in keys_to_remove {
+        if let Some(item) = crates_io_table.remove(&key) {
+            if let Some(path) = item
+                .as_inline_table()
+                .and_then(|t| t.get("path"))
+                .and_then(|v| v.as_str())
+            {
+                patch_dirs.push(PathBuf::from(path));
+            }
+        }
+    }
+
+    if crates_io_table.is_empty() {
+        patch_table.remove("crates-io");
+    }
+    if patch_table.is_empty() {
+        root_table.remove("patch");
+    }
+
+    Ok(patch_dirs)
+}
+
 pub(crate) fn query_metadata(cdir: &Path) -> Result<Metadata> {
     let mut metadata_cmd = cargo_metadata::MetadataCommand::new();
     metadata_cmd.current_dir(cdir);
@@ -104,14 +169,229 @@ pub(crate) fn parse_manifest(manifest_path: &Path) -> Result<Manifest> {
         .with_context(|| "Failed to parse manifest")
 }
 
+/// Find every package named `package_name` in `packages`. A dependency graph can
+/// contain more than one semver-incompatible copy of the same crate, so this
+/// returns all of them rather than erroring on duplicates.
 pub(crate) fn find_package<'a>(
     packages: impl Iterator<Item = &'a Package>,
     package_name: &str,
-) -> Result<&'a Package> {
+) -> Result<Vec<&'a Package>> {
     let pkgs: Vec<_> = packages.filter(|p| p.name == package_name).collect();
+    if pkgs.is_empty() {
+        bail!("failed to find package {}", package_name);
+    }
+    Ok(pkgs)
+}
+
+/// Like [`find_package`], but errors if more than one package matches. Use this
+/// where a single unambiguous package is required, e.g. looking up a crate within
+/// its own checked-out workspace.
+pub(crate) fn find_one_package<'a>(
+    packages: impl Iterator<Item = &'a Package>,
+    package_name: &str,
+) -> Result<&'a Package> {
+    let pkgs = find_package(packages, package_name)?;
     match pkgs.len() {
-        0 => bail!("failed to find package {}", package_name),
         1 => Ok(pkgs[0]),
         _ => bail!("found multiple packages with name {}", package_name),
     }
 }
+
+/// Find the package named `package_name` at exactly `version`, if the resolved graph
+/// contains one. This is the same name-and-version matching that lets multiple
+/// patched versions of a crate be told apart, reused here to check whether a given
+/// patch actually took effect.
+pub(crate) fn find_package_version<'a>(
+    mut packages: impl Iterator<Item = &'a Package>,
+    package_name: &str,
+    version: &Version,
+) -> Option<&'a Package> {
+    packages.find(|p| p.name == package_name && &p.version == version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(toml: &str) -> Manifest {
+        toml.parse::<Manifest>().unwrap()
+    }
+
+    fn empty_manifest() -> Manifest {
+        manifest("[package]\nname = \"demo\"\nversion = \"0.1.0\"\n")
+    }
+
+    fn package_json(name: &str, version: &str, source: Option<&str>) -> serde_json::Value {
+        serde_json::json!({
+            "name": name,
+            "version": version,
+            "id": format!("{} {} ({})", name, version, source.unwrap_or("path+file:///tmp")),
+            "license": null,
+            "license_file": null,
+            "description": null,
+            "source": source,
+            "dependencies": [],
+            "targets": [],
+            "features": {},
+            "manifest_path": format!("/tmp/{}-{}/Cargo.toml", name, version),
+            "categories": [],
+            "keywords": [],
+            "readme": null,
+            "repository": null,
+            "homepage": null,
+            "documentation": null,
+            "edition": "2018",
+            "links": null,
+            "default_run": null,
+            "rust_version": null,
+            "publish": null,
+            "metadata": null,
+            "authors": []
+        })
+    }
+
+    fn sample_packages() -> Vec<Package> {
+        let json = serde_json::json!([
+            package_json("serde", "1.0.0", Some("registry+https://github.com/rust-lang/crates.io-index")),
+            package_json("serde", "2.0.0", None),
+            package_json("anyhow", "1.0.0", Some("registry+https://github.com/rust-lang/crates.io-index")),
+        ]);
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn insert_patch_adds_path_package_and_pinned_version() {
+        let mut m = empty_manifest();
+        manifest_insert_patch(
+            &mut m,
+            "serde-1",
+            "serde",
+            &Version::parse("1.0.0").unwrap(),
+            Path::new("/tmp/serde-1"),
+        )
+        .unwrap();
+
+        let toml = m.data.to_string();
+        assert!(toml.contains("[patch.crates-io]"));
+        assert!(toml.contains("serde-1"));
+        assert!(toml.contains("package = \"serde\""));
+        assert!(toml.contains("version = \"=1.0.0\""));
+        assert!(toml.contains("/tmp/serde-1"));
+    }
+
+    #[test]
+    fn remove_patch_prunes_empty_tables() {
+        let mut m = empty_manifest();
+        manifest_insert_patch(
+            &mut m,
+            "serde",
+            "serde",
+            &Version::parse("1.0.0").unwrap(),
+            Path::new("/tmp/serde"),
+        )
+        .unwrap();
+
+        let removed = manifest_remove_patch(&mut m, "serde").unwrap();
+        assert_eq!(removed, vec![PathBuf::from("/tmp/serde")]);
+        assert!(!m.data.to_string().contains("[patch"));
+    }
+
+    #[test]
+    fn remove_patch_removes_every_version_of_a_crate() {
+        let mut m = empty_manifest();
+        manifest_insert_patch(
+            &mut m,
+            "serde-1",
+            "serde",
+            &Version::parse("1.0.0").unwrap(),
+            Path::new("/tmp/serde-1"),
+        )
+        .unwrap();
+        manifest_insert_patch(
+            &mut m,
+            "serde-2",
+            "serde",
+            &Version::parse("2.0.0").unwrap(),
+            Path::new("/tmp/serde-2"),
+        )
+        .unwrap();
+
+        let mut removed = manifest_remove_patch(&mut m, "serde").unwrap();
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![PathBuf::from("/tmp/serde-1"), PathBuf::from("/tmp/serde-2")]
+        );
+        assert!(!m.data.to_string().contains("[patch"));
+    }
+
+    #[test]
+    fn remove_patch_leaves_other_crates_alone() {
+        let mut m = empty_manifest();
+        manifest_insert_patch(
+            &mut m,
+            "serde",
+            "serde",
+            &Version::parse("1.0.0").unwrap(),
+            Path::new("/tmp/serde"),
+        )
+        .unwrap();
+        manifest_insert_patch(
+            &mut m,
+            "anyhow",
+            "anyhow",
+            &Version::parse("1.0.0").unwrap(),
+            Path::new("/tmp/anyhow"),
+        )
+        .unwrap();
+
+        let removed = manifest_remove_patch(&mut m, "serde").unwrap();
+        assert_eq!(removed, vec![PathBuf::from("/tmp/serde")]);
+        assert!(m.data.to_string().contains("anyhow"));
+    }
+
+    #[test]
+    fn remove_patch_is_a_noop_when_absent() {
+        let mut m = empty_manifest();
+        assert!(manifest_remove_patch(&mut m, "serde").unwrap().is_empty());
+    }
+
+    #[test]
+    fn find_package_version_matches_name_and_version() {
+        let packages = sample_packages();
+
+        let found =
+            find_package_version(packages.iter(), "serde", &Version::parse("2.0.0").unwrap())
+                .unwrap();
+        assert_eq!(found.version, Version::parse("2.0.0").unwrap());
+
+        assert!(
+            find_package_version(packages.iter(), "serde", &Version::parse("3.0.0").unwrap())
+                .is_none()
+        );
+        assert!(
+            find_package_version(packages.iter(), "missing", &Version::parse("1.0.0").unwrap())
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn find_package_returns_every_matching_version() {
+        let packages = sample_packages();
+        let found = find_package(packages.iter(), "serde").unwrap();
+        assert_eq!(found.len(), 2);
+    }
+
+    #[test]
+    fn find_package_errors_when_absent() {
+        let packages = sample_packages();
+        assert!(find_package(packages.iter(), "missing").is_err());
+    }
+
+    #[test]
+    fn find_one_package_errors_on_ambiguous_version() {
+        let packages = sample_packages();
+        assert!(find_one_package(packages.iter(), "serde").is_err());
+        assert!(find_one_package(packages.iter(), "anyhow").is_ok());
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+/// Files `cargo package` generates or rewrites during publish, and so are expected to
+/// be missing from (or differ from) a plain VCS checkout.
+/// See https://doc.rust-lang.org/cargo/commands/cargo-package.html
+const IGNORED_FILES: &[&str] = &[".cargo_vcs_info.json", ".cargo-ok", "Cargo.toml.orig"];
+
+/// The result of comparing a VCS checkout against the unpacked `.crate` archive that
+/// `cargo package` produced from it.
+#[derive(Debug, Default)]
+pub(crate) struct VerifyReport {
+    /// Files present in both trees with differing contents.
+    pub mismatched: Vec<PathBuf>,
+    /// Files in the archive that are missing from the VCS checkout.
+    pub missing_from_vcs: Vec<PathBuf>,
+    /// Files in the VCS checkout that the archive doesn't contain.
+    pub extra_in_vcs: Vec<PathBuf>,
+}
+
+impl VerifyReport {
+    pub(crate) fn is_clean(&self) -> bool {
+        self.mismatched.is_empty() && self.missing_from_vcs.is_empty() && self.extra_in_vcs.is_empty()
+    }
+}
+
+fn sha256_file(path: &Path) -> Result<[u8; 32]> {
+    let contents = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(Sha256::digest(&contents).into())
+}
+
+fn relative_files(root: &Path) -> Result<BTreeSet<PathBuf>> {
+    fn walk(dir: &Path, root: &Path, out: &mut BTreeSet<PathBuf>) -> Result<()> {
+        for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.file_name() == Some(std::ffi::OsStr::new(".git")) {
+                // Not part of the published crate; walking it is both wasted work and
+                // guaranteed to show up as "extra" in every non-workspace checkout.
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                walk(&path, root, out)?;
+            } else {
+                out.insert(path.strip_prefix(root).unwrap().to_path_buf());
+            }
+        }
+        Ok(())
+    }
+
+    let mut out = BTreeSet::new();
+    walk(root, root, &mut out)?;
+    Ok(out)
+}
+
+fn is_ignored(rel_path: &Path) -> bool {
+    rel_path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .map(|f| IGNORED_FILES.contains(&f))
+        .unwrap_or(false)
+}
+
+/// Compare a VCS checkout against the unpacked contents of the published `.crate`
+/// archive, reporting any files that differ, are missing, or are extra.
+///
+/// `cargo package` rewrites `Cargo.toml` during publish (normalizing paths, inlining
+/// workspace inheritance, etc), so it's expected-to-differ rather than a mismatch.
+pub(crate) fn verify_vcs_matches_archive(vcs_dir: &Path, archive_dir: &Path) -> Result<VerifyReport> {
+    let vcs_files = relative_files(vcs_dir)?;
+    let archive_files = relative_files(archive_dir)?;
+
+    let mut report = VerifyReport::default();
+
+    for rel_path in vcs_files.union(&archive_files) {
+        if is_ignored(rel_path) || rel_path == Path::new("Cargo.toml") {
+            continue;
+        }
+
+        match (vcs_files.contains(rel_path), archive_files.contains(rel_path)) {
+            (true, true) => {
+                if sha256_file(&vcs_dir.join(rel_path))? != sha256_file(&archive_dir.join(rel_path))? {
+                    report.mismatched.push(rel_path.clone());
+                }
+            }
+            (true, false) => report.extra_in_vcs.push(rel_path.clone()),
+            (false, true) => report.missing_from_vcs.push(rel_path.clone()),
+            (false, false) => unreachable!(),
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, rel_path: &str, contents: &str) {
+        let path = dir.join(rel_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn ignores_git_directory_in_vcs_checkout() {
+        let vcs_dir = tempfile::tempdir().unwrap();
+        let archive_dir = tempfile::tempdir().unwrap();
+
+        write(vcs_dir.path(), "src/lib.rs", "fn main() {}");
+        write(archive_dir.path(), "src/lib.rs", "fn main() {}");
+
+        // A real checkout has a .git directory full of files the archive never has.
+        write(vcs_dir.path(), ".git/HEAD", "ref: refs/heads/main");
+        write(vcs_dir.path(), ".git/objects/pack/pack-abc.pack", "binary");
+
+        let report = verify_vcs_matches_archive(vcs_dir.path(), archive_dir.path()).unwrap();
+        assert!(report.is_clean(), "{:?}", report);
+    }
+
+    #[test]
+    fn is_clean_when_vcs_and_archive_match() {
+        let vcs_dir = tempfile::tempdir().unwrap();
+        let archive_dir = tempfile::tempdir().unwrap();
+
+        write(vcs_dir.path(), "src/lib.rs", "fn main() {}");
+        write(archive_dir.path(), "src/lib.rs", "fn main() {}");
+
+        let report = verify_vcs_matches_archive(vcs_dir.path(), archive_dir.path()).unwrap();
+        assert!(report.is_clean(), "{:?}", report);
+    }
+
+    #[test]
+    fn detects_mismatched_missing_and_extra_files() {
+        let vcs_dir = tempfile::tempdir().unwrap();
+        let archive_dir = tempfile::tempdir().unwrap();
+
+        write(vcs_dir.path(), "src/lib.rs", "fn main() {}");
+        write(archive_dir.path(), "src/lib.rs", "fn main() {} // published");
+
+        write(vcs_dir.path(), "src/only_in_vcs.rs", "");
+        write(archive_dir.path(), "src/only_in_archive.rs", "");
+
+        let report = verify_vcs_matches_archive(vcs_dir.path(), archive_dir.path()).unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(report.mismatched, vec![PathBuf::from("src/lib.rs")]);
+        assert_eq!(report.extra_in_vcs, vec![PathBuf::from("src/only_in_vcs.rs")]);
+        assert_eq!(
+            report.missing_from_vcs,
+            vec![PathBuf::from("src/only_in_archive.rs")]
+        );
+    }
+
+    #[test]
+    fn ignores_cargo_toml_and_publish_generated_files() {
+        let vcs_dir = tempfile::tempdir().unwrap();
+        let archive_dir = tempfile::tempdir().unwrap();
+
+        write(vcs_dir.path(), "Cargo.toml", "[package]\nworkspace = true\n");
+        write(archive_dir.path(), "Cargo.toml", "[package]\nname = \"demo\"\n");
+        write(archive_dir.path(), ".cargo_vcs_info.json", "{}");
+        write(archive_dir.path(), ".cargo-ok", "");
+        write(vcs_dir.path(), "Cargo.toml.orig", "ignored-in-archive-anyway");
+
+        let report = verify_vcs_matches_archive(vcs_dir.path(), archive_dir.path()).unwrap();
+        assert!(report.is_clean(), "{:?}", report);
+    }
+
+    #[test]
+    fn is_ignored_matches_only_known_publish_artifacts() {
+        assert!(is_ignored(Path::new(".cargo_vcs_info.json")));
+        assert!(is_ignored(Path::new("nested/dir/.cargo-ok")));
+        assert!(is_ignored(Path::new("Cargo.toml.orig")));
+        assert!(!is_ignored(Path::new("Cargo.toml")));
+        assert!(!is_ignored(Path::new("src/lib.rs")));
+    }
+}